@@ -0,0 +1,55 @@
+//! Buffered diagnostic log of discarded/corrupt frames.
+//!
+//! Parse failures in `parse_bytes_raw`/`process_buffer` are otherwise silent, which makes
+//! flaky serial links impossible to debug from the Python side. This module keeps a bounded,
+//! in-memory history of what got dropped and why so a supervisor can poll it without
+//! perturbing the hot path.
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// A single discarded-frame record.
+#[derive(Clone)]
+pub struct LogRecord {
+    pub timestamp_micros: u64,
+    pub reason: &'static str,
+    pub bytes_lost: usize,
+}
+
+lazy_static! {
+    static ref START: Instant = Instant::now();
+    static ref LOG: Mutex<VecDeque<LogRecord>> = Mutex::new(VecDeque::new());
+    static ref CAPACITY: Mutex<usize> = Mutex::new(256);
+}
+
+/// Record a discarded frame. A cheap push under the lock; a no-op when the capacity is 0.
+pub fn log_fault(reason: &'static str, bytes_lost: usize) {
+    let capacity = *CAPACITY.lock().expect("Log capacity lock was poisoned");
+    if capacity == 0 {
+        return;
+    }
+
+    let elapsed = START.elapsed();
+    let timestamp_micros = elapsed.as_secs() * 1_000_000 + elapsed.subsec_micros() as u64;
+
+    let mut log = LOG.lock().expect("Diagnostic log lock was poisoned");
+    if log.len() >= capacity {
+        log.pop_front();
+    }
+    log.push_back(LogRecord { timestamp_micros: timestamp_micros, reason: reason, bytes_lost: bytes_lost });
+}
+
+/// Remove and return every buffered record, oldest first.
+pub fn drain() -> Vec<LogRecord> {
+    let mut log = LOG.lock().expect("Diagnostic log lock was poisoned");
+    log.drain(..).collect()
+}
+
+/// Set the maximum number of buffered records, evicting the oldest entries if shrinking.
+pub fn set_capacity(capacity: usize) {
+    *CAPACITY.lock().expect("Log capacity lock was poisoned") = capacity;
+    let mut log = LOG.lock().expect("Diagnostic log lock was poisoned");
+    while log.len() > capacity {
+        log.pop_front();
+    }
+}
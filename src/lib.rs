@@ -11,14 +11,16 @@ use std::panic;
 use std::collections::VecDeque;
 use std::cell::RefCell;
 
-use cpython::{Python, PyResult, PyObject, PyBytes, PythonObject, PyErr, ToPyObject, PyTuple};
+use cpython::{Python, PyResult, PyObject, PyBytes, PyList, PyDict, PythonObject, PyErr, ToPyObject, PyTuple};
+use cpython::buffer::PyBuffer;
 use cpython::exc;
 
 #[macro_use]
 mod utils;
 mod parsing;
-use parsing::{initialize_parser_maps, parse_device_data};
-use utils::objectify;
+mod diagnostics;
+use parsing::{initialize_parser_maps, parse_device_data, encode_device_write, encode_subscription_request, error_code};
+use utils::{objectify, value_error};
 
 
 /// Change a Rust panic into a Python exception. Put this on all wrapper methods unless
@@ -45,32 +47,63 @@ struct RawMessage {
     pub payload: Vec<u8>,
 }
 
-/// Try to parse `bytes` into a packet.
-fn parse_bytes_raw(bytes: &[u8]) -> Option<RawMessage> {
+/// Outcome of trying to parse a packet starting at a delimiter byte.
+enum ParseOutcome {
+    /// Not enough data has arrived yet to tell whether this is a complete packet.
+    Incomplete,
+    /// The frame at this delimiter is corrupt. `error_code` is one of `ERROR_CODES`, `reason`
+    /// is a human-readable cause for the diagnostic log, and `consumed` is how many bytes
+    /// (including the leading delimiter) to skip.
+    Malformed { error_code: u8, reason: &'static str, consumed: usize },
+    /// A full packet was parsed. `consumed` is how many bytes (including the leading
+    /// delimiter) it occupied.
+    Complete { message: RawMessage, consumed: usize },
+}
+
+/// Try to parse `bytes` into a packet. `bytes` is expected to start with the `0x00` frame
+/// delimiter.
+fn parse_bytes_raw(bytes: &[u8]) -> ParseOutcome {
+    if bytes.len() < 2 {
+        return ParseOutcome::Incomplete;
+    }
     let (cobs_frame, msg_size) = (bytes[0], bytes[1] as usize);
-    if cobs_frame != 0 || bytes.len() < msg_size + 2 {
-        return None;
+    if cobs_frame != 0 {
+        return ParseOutcome::Malformed {
+            error_code: error_code("UnexpectedDelimiter"), reason: "bad length", consumed: 1
+        };
+    }
+    if bytes.len() < msg_size + 2 {
+        return ParseOutcome::Incomplete;
     }
 
     let message = cobs_decode(&bytes[2..msg_size + 2]);
     let message_len = message.len();
     if message_len < 2 {
-        return None;
+        return ParseOutcome::Malformed {
+            error_code: error_code("UnexpectedDelimiter"), reason: "COBS failure", consumed: msg_size + 2
+        };
     }
     let (message_id, payload_len) = (message[0], message[1] as usize);
     if message_len < 2 + payload_len + 1 {
-        return None;
+        return ParseOutcome::Malformed {
+            error_code: error_code("UnexpectedDelimiter"), reason: "COBS failure", consumed: msg_size + 2
+        };
     }
     let payload = &message[2..2 + payload_len];
     let msg_checksum = message[2 + payload_len];
     if msg_checksum != checksum(&message[..message_len - 1]) {
-        return None;
+        return ParseOutcome::Malformed {
+            error_code: error_code("ChecksumError"), reason: "checksum mismatch", consumed: msg_size + 2
+        };
     }
 
-    Some(RawMessage {
-        message_id: message_id,
-        payload: payload.into()
-    })
+    ParseOutcome::Complete {
+        message: RawMessage {
+            message_id: message_id,
+            payload: payload.into()
+        },
+        consumed: msg_size + 2,
+    }
 }
 
 
@@ -125,8 +158,106 @@ py_class!(class RingBuffer |py| {
         let buffer = self.buffer(py).borrow();
         Ok(buffer.iter().cloned().collect())
     }
+
+    def extend_many(&self, chunks: PyList) -> PyResult<PyObject> {
+        let mut buffer = self.buffer(py).borrow_mut();
+        for chunk in chunks.iter(py) {
+            let bytes: PyBytes = chunk.extract(py)?;
+            buffer.extend(bytes.data(py).iter().cloned());
+        }
+        Ok(py.None())
+    }
+
+    def peek(&self, n: usize) -> PyResult<Vec<u8>> {
+        let buffer = self.buffer(py).borrow();
+        let (first_half, second_half) = buffer.as_slices();
+        let n = n.min(buffer.len());
+
+        let mut out = Vec::with_capacity(n);
+        if n <= first_half.len() {
+            out.extend_from_slice(&first_half[..n]);
+        } else {
+            out.extend_from_slice(first_half);
+            out.extend_from_slice(&second_half[..n - first_half.len()]);
+        }
+        Ok(out)
+    }
+
+    def read_into(&self, out: PyObject, offset: usize) -> PyResult<usize> {
+        let buffer = self.buffer(py).borrow();
+        let (first_half, second_half) = buffer.as_slices();
+        let total = first_half.len() + second_half.len();
+
+        let py_buffer = PyBuffer::get(py, &out)?;
+        py_assert!(py, !py_buffer.readonly(), "out must be a writable buffer");
+
+        let len_bytes = py_buffer.len_bytes();
+        let to_copy = total.saturating_sub(offset).min(len_bytes);
+
+        // `copy_from_slice` requires the source to match `len_bytes` exactly, so fill a
+        // scratch buffer of that size (zero-padding anything past `to_copy`) and hand the
+        // whole thing to the safe buffer-protocol API instead of poking at raw pointers.
+        let mut scratch = vec![0u8; len_bytes];
+        let first_start = offset.min(first_half.len());
+        let first_len = (first_half.len() - first_start).min(to_copy);
+        scratch[..first_len].copy_from_slice(&first_half[first_start..first_start + first_len]);
+
+        let second_start = offset.saturating_sub(first_half.len());
+        let second_len = to_copy - first_len;
+        scratch[first_len..first_len + second_len].copy_from_slice(&second_half[second_start..second_start + second_len]);
+
+        py_buffer.copy_from_slice(py, &scratch)?;
+        Ok(to_copy)
+    }
+});
+
+/// A parse fault detected by `process_all`, carrying one of the codes in `ERROR_CODES`.
+py_class!(class ParseFault |py| {
+    data error_code: u8;
+    data bytes_lost: usize;
+
+    def __new__(_cls, error_code: u8, bytes_lost: usize) -> PyResult<ParseFault> {
+        ParseFault::create_instance(py, error_code, bytes_lost)
+    }
+
+    def __repr__(&self) -> PyResult<String> {
+        Ok(format!("ParseFault(error_code={}, bytes_lost={})", self.error_code(py), self.bytes_lost(py)))
+    }
+});
+
+/// A buffered diagnostic log entry for a discarded/corrupt frame.
+py_class!(class LogEntry |py| {
+    data timestamp_micros: u64;
+    data reason: String;
+    data bytes_lost: usize;
+
+    def __new__(_cls, timestamp_micros: u64, reason: String, bytes_lost: usize) -> PyResult<LogEntry> {
+        LogEntry::create_instance(py, timestamp_micros, reason, bytes_lost)
+    }
+
+    def __repr__(&self) -> PyResult<String> {
+        Ok(format!("LogEntry(timestamp_micros={}, reason={:?}, bytes_lost={})",
+                   self.timestamp_micros(py), self.reason(py), self.bytes_lost(py)))
+    }
 });
 
+/// Drain and return every buffered diagnostic log entry, oldest first.
+fn drain_logs(gil: Python) -> PyResult<PyList> {
+    let mut entries = Vec::new();
+    for record in diagnostics::drain() {
+        let entry = LogEntry::create_instance(gil, record.timestamp_micros, record.reason.to_string(), record.bytes_lost)?;
+        entries.push(objectify(gil, entry));
+    }
+    Ok(PyList::new(gil, &entries))
+}
+
+/// Set the maximum number of diagnostic log entries to buffer, evicting the oldest entries
+/// if shrinking. A capacity of 0 disables logging entirely.
+fn set_log_capacity(gil: Python, capacity: usize) -> PyResult<PyObject> {
+    diagnostics::set_capacity(capacity);
+    Ok(gil.None())
+}
+
 const DELIMITER: u8 = 0;
 /// Search for packets in `buffer`, decoding them if found.
 ///
@@ -139,25 +270,124 @@ fn process_buffer(gil: Python, buffer: RingBuffer) -> PyResult<PyObject> {
     if let Some(curr_idx) = memchr::memchr(DELIMITER, data) {
         let chopped_data = &data[curr_idx..];
         match parse_bytes_raw(chopped_data) {
-            Some(packet) => {
+            ParseOutcome::Complete { message, consumed } => {
                 // Chop off the packet data so we don't parse it again
-                buffer.chop_front(gil, curr_idx + 1)?;
-                let tuple = PyTuple::new(gil, &[objectify(gil, packet.message_id),
-                                                objectify(gil, PyBytes::new(gil, &packet.payload))]);
+                buffer.chop_front(gil, curr_idx + consumed)?;
+                let tuple = PyTuple::new(gil, &[objectify(gil, message.message_id),
+                                                objectify(gil, PyBytes::new(gil, &message.payload))]);
                 return Ok(objectify(gil, tuple));
             }
-            None => {
-                // Jump to the next packet, if there is one
-                if let Some(next_idx) = memchr::memchr(DELIMITER, &chopped_data[1..]) {
-                    buffer.chop_front(gil, curr_idx + next_idx + 1)?;
-                }
+            ParseOutcome::Malformed { reason, consumed, .. } => {
+                // Skip the corrupt frame so we don't loop on it
+                diagnostics::log_fault(reason, curr_idx + consumed);
+                buffer.chop_front(gil, curr_idx + consumed)?;
             }
+            ParseOutcome::Incomplete => {}
         }
     }
 
     return Ok(py_none);
 }
 
+/// Drain every complete packet currently in `buffer`, returning records in the order they
+/// appear.
+///
+/// Each record is either:
+/// - a `(message_id, payload)` tuple for a successfully parsed packet
+/// - a `ParseFault` for a corrupt frame that was detected and skipped
+///
+/// Unlike `process_buffer`, this never silently drops a corrupt frame: every fault is
+/// surfaced so the caller can implement retransmit/recovery logic.
+fn process_all(gil: Python, buffer: RingBuffer) -> PyResult<PyList> {
+    let data = buffer.get_data(gil)?;
+    let mut records = Vec::new();
+    let mut consumed_total = 0usize;
+
+    while let Some(curr_idx) = memchr::memchr(DELIMITER, &data[consumed_total..]) {
+        let chopped_data = &data[consumed_total + curr_idx..];
+        match parse_bytes_raw(chopped_data) {
+            ParseOutcome::Incomplete => break,
+            ParseOutcome::Malformed { error_code, reason, consumed } => {
+                diagnostics::log_fault(reason, curr_idx + consumed);
+                let fault = ParseFault::create_instance(gil, error_code, curr_idx + consumed)?;
+                records.push(objectify(gil, fault));
+                consumed_total += curr_idx + consumed;
+            }
+            ParseOutcome::Complete { message, consumed } => {
+                let tuple = PyTuple::new(gil, &[objectify(gil, message.message_id),
+                                                objectify(gil, PyBytes::new(gil, &message.payload))]);
+                records.push(objectify(gil, tuple));
+                consumed_total += curr_idx + consumed;
+            }
+        }
+    }
+
+    buffer.chop_front(gil, consumed_total)?;
+    Ok(PyList::new(gil, &records))
+}
+
+/// COBS-encode `data`, the inverse of `cobs_decode`.
+fn cobs_encode(data: &[u8]) -> Vec<u8> {
+    let mut output = vec![0];
+    let mut code_idx = 0usize;
+    let mut code = 1u8;
+
+    for &byte in data {
+        if byte == 0 {
+            output[code_idx] = code;
+            code = 1;
+            code_idx = output.len();
+            output.push(0);
+        } else {
+            output.push(byte);
+            code += 1;
+            if code == 0xFF {
+                output[code_idx] = code;
+                code = 1;
+                code_idx = output.len();
+                output.push(0);
+            }
+        }
+    }
+    output[code_idx] = code;
+    output
+}
+
+fn cobs_encode_wrapper(gil: Python, data: PyBytes) -> PyResult<PyBytes> {
+    Ok(PyBytes::new(gil, &cobs_encode(data.data(gil))))
+}
+
+/// Build a wire packet carrying `message_id` and `payload`, the inverse of `parse_bytes_raw`.
+fn encode_message(message_id: u8, payload: &[u8]) -> Result<Vec<u8>, String> {
+    if payload.len() > 0xFF {
+        return Err(format!("payload is {} bytes, but a message can carry at most {}", payload.len(), 0xFF));
+    }
+
+    let mut message = Vec::with_capacity(2 + payload.len() + 1);
+    message.push(message_id);
+    message.push(payload.len() as u8);
+    message.extend_from_slice(payload);
+    message.push(checksum(&message));
+
+    let cobs_frame = cobs_encode(&message);
+    if cobs_frame.len() > 0xFF {
+        return Err(format!("encoded message is {} bytes, but a frame can carry at most {}", cobs_frame.len(), 0xFF));
+    }
+
+    let mut framed = Vec::with_capacity(2 + cobs_frame.len());
+    framed.push(0x00);
+    framed.push(cobs_frame.len() as u8);
+    framed.extend_from_slice(&cobs_frame);
+    Ok(framed)
+}
+
+fn encode_message_wrapper(gil: Python, message_id: u8, payload: PyBytes) -> PyResult<PyBytes> {
+    match encode_message(message_id, payload.data(gil)) {
+        Ok(framed) => Ok(PyBytes::new(gil, &framed)),
+        Err(msg) => Err(value_error(gil, msg)),
+    }
+}
+
 /// COBS-decode `data`.
 fn cobs_decode(data: &[u8]) -> Vec<u8> {
     let mut output = Vec::new();
@@ -192,17 +422,56 @@ fn checksum_wrapper(gil: Python, message: PyBytes) -> PyResult<u8> {
 
 py_module_initializer!(hibike_packet, inithibike_packet, PyInit_hibike_packet, |py, m| {
     m.add(py, "process_buffer", py_fn!(py, process_buffer(buffer: RingBuffer)))?;
+    m.add(py, "process_all", py_fn!(py, process_all(buffer: RingBuffer)))?;
+    m.add(py, "drain_logs", py_fn!(py, drain_logs()))?;
+    m.add(py, "set_log_capacity", py_fn!(py, set_log_capacity(capacity: usize)))?;
     m.add(py, "checksum", py_fn!(py, checksum_wrapper(message: PyBytes)))?;
+    m.add(py, "cobs_encode", py_fn!(py, cobs_encode_wrapper(data: PyBytes)))?;
+    m.add(py, "encode_message", py_fn!(py, encode_message_wrapper(message_id: u8, payload: PyBytes)))?;
     m.add(py, "initialize_parser_maps", py_fn!(py, initialize_parser_maps(config_data: &str)))?;
     m.add(py, "parse_device_data", py_fn!(py, parse_device_data(payload: PyBytes, device_id: u16)))?;
+    m.add(py, "encode_device_write", py_fn!(py, encode_device_write(device_id: u16, params: PyDict)))?;
+    m.add(py, "encode_subscription_request", py_fn!(py, encode_subscription_request(device_id: u16, params: PyList, delay: u16)))?;
     m.add_class::<RingBuffer>(py)?;
+    m.add_class::<ParseFault>(py)?;
+    m.add_class::<LogEntry>(py)?;
     Ok(())
 });
 
 #[cfg(test)]
 mod tests {
+    use super::{cobs_decode, cobs_encode, encode_message, parse_bytes_raw, ParseOutcome};
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn cobs_round_trip() {
+        let data = vec![1, 2, 0, 3, 4, 5, 0, 0, 6];
+        let encoded = cobs_encode(&data);
+        assert!(!encoded.contains(&0), "COBS-encoded data must not contain a zero byte");
+        assert_eq!(cobs_decode(&encoded), data);
+    }
+
+    #[test]
+    fn encode_message_round_trips_through_parse_bytes_raw() {
+        let payload = vec![1, 2, 3, 4, 5];
+        let framed = encode_message(0x13, &payload).unwrap();
+        match parse_bytes_raw(&framed) {
+            ParseOutcome::Complete { message, consumed } => {
+                assert_eq!(message.message_id, 0x13);
+                assert_eq!(message.payload, payload);
+                assert_eq!(consumed, framed.len());
+            }
+            _ => panic!("expected a complete packet"),
+        }
+    }
+
+    #[test]
+    fn encode_message_rejects_oversized_payload() {
+        let payload = vec![0u8; 300];
+        assert!(encode_message(0x13, &payload).is_err());
+    }
 }
@@ -5,13 +5,13 @@ extern crate byteorder;
 use ::utils::{value_error, objectify};
 
 use std::collections::HashMap;
-use std::sync::RwLock;
+use std::sync::{Arc, RwLock};
 use std::io::Cursor;
 use std::io;
 
-use cpython::{Python, PyResult, PyObject, PyBytes, PyList, PyErr, ToPyObject, exc};
+use cpython::{Python, PyResult, PyObject, PyBytes, PyList, PyDict, PyErr, ToPyObject, exc};
 
-use self::byteorder::{LittleEndian, ReadBytesExt};
+use self::byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 
 type DeviceId = u16;
 type ParamMap = HashMap<String, Parameter>;
@@ -27,7 +27,11 @@ lazy_static! {
         RwLock::new(HashMap::new())
     };
 
-    static ref DEVICE_MAP: RwLock<HashMap<DeviceId, Device>> = {
+    // Per-device params indexed by bit position (slot `i` is bit `i`, `None` for an unused
+    // bit), for the decode hot path. Wrapped in an `Arc` so `parse_device_data` can clone the
+    // handle and drop the lock before decoding, instead of holding the lock (and cloning every
+    // param name) for the whole call.
+    static ref PARAM_INDEX: RwLock<HashMap<DeviceId, Arc<Vec<Option<Parameter>>>>> = {
         RwLock::new(HashMap::new())
     };
 
@@ -53,6 +57,11 @@ lazy_static! {
     };
 }
 
+/// Look up an error code by name (see `ERROR_CODES`).
+pub fn error_code(name: &str) -> u8 {
+    ERROR_CODES[name]
+}
+
 /// A sensor.
 #[derive(Clone, Deserialize)]
 pub struct Device {
@@ -101,46 +110,35 @@ pub struct Parameter {
 /// This function must be called before `parse_device_data`.
 pub fn initialize_parser_maps(gil: Python, config_data: &str) -> PyResult<PyObject> {
     // Try to parse the list of devices
-    let mut parsed_data: Vec<Device> = match serde_json::from_str(config_data) {
+    let parsed_data: Vec<Device> = match serde_json::from_str(config_data) {
         Ok(dev_list) => dev_list,
         Err(e) => {
             return Err(value_error(gil, format!("could not parse device parameters: {}", e)));
         }
     };
-    let mut device_map = DEVICE_MAP.write().expect("Device map lock was poisoned");
     let mut param_map = PARAM_MAP.write().expect("Param map lock was poisoned");
+    let mut param_index = PARAM_INDEX.write().expect("Param index lock was poisoned");
 
-    parsed_data.into_iter().for_each(|device| device_map.insert(device.id, device));
-
-    for (device_id, device) in device_map.clone() {
-        let mut params = HashMap::new();
+    for device in parsed_data {
+        let mut by_name = HashMap::new();
+        // Slot by actual bit position rather than array position: param numbers aren't
+        // guaranteed to be a dense `0..len()` run (e.g. a reserved bit can leave a gap).
+        let max_number = device.params.iter().map(|param| param.number as usize).max();
+        let mut by_bit: Vec<Option<Parameter>> = match max_number {
+            Some(max) => vec![None; max + 1],
+            None => Vec::new(),
+        };
         for param in device.params {
-            params.insert(param.name.clone(), param);
+            by_bit[param.number as usize] = Some(param.clone());
+            by_name.insert(param.name.clone(), param);
         }
-        param_map.insert(device_id, params);
+        param_map.insert(device.id, by_name);
+        param_index.insert(device.id, Arc::new(by_bit));
     }
 
     Ok(gil.None())
 }
 
-/// Decode `bitmask` into human-readable names.
-fn decode_params(device_id: u16, bitmask: u16) -> Vec<String> {
-    let device_map = DEVICE_MAP.read().expect("Device map lock was poisoned");
-    let device = &device_map[&device_id];
-    let mut names: Vec<String> = Vec::with_capacity(16);
-    for i in 0..16 {
-        if i >= device.params.len() {
-            break;
-        }
-        if bitmask & (1 << i) != 0 {
-            let name: String = device.params[i].name.clone();
-            names.push(name);
-        }
-    }
-
-    names
-}
-
 fn try_read<T>(gil: Python, maybe_param: io::Result<T>) -> PyResult<PyObject> where T: ToPyObject {
     py_assert!(gil, maybe_param.is_ok(), "packet is missing a parameter");
     Ok(objectify(gil, maybe_param.unwrap()))
@@ -152,19 +150,32 @@ fn try_read<T>(gil: Python, maybe_param: io::Result<T>) -> PyResult<PyObject> wh
 /// - `device_id` is invalid
 /// - `payload`'s length is too short
 pub fn parse_device_data(gil: Python, payload: PyBytes, device_id: u16) -> PyResult<PyList> {
-    let device_map = DEVICE_MAP.read().expect("Device map lock was poisoned");
-    py_assert!(gil, device_map.contains_key(&device_id), format!("invalid device_id: {}", device_id));
+    // Clone the `Arc` and drop the lock immediately; decoding doesn't need to hold it.
+    let params = {
+        let param_index = PARAM_INDEX.read().expect("Param index lock was poisoned");
+        py_assert!(gil, param_index.contains_key(&device_id), format!("invalid device_id: {}", device_id));
+        Arc::clone(&param_index[&device_id])
+    };
+
     let raw_bytes = payload.data(gil);
     py_assert!(gil, raw_bytes.len() >= 2, "Packet payload is too short");
 
     let mut cursor = Cursor::new(raw_bytes);
     let bitmask = cursor.read_u16::<LittleEndian>().unwrap();
-    let names: Vec<String> = decode_params(device_id, bitmask);
 
-    let mut values = Vec::with_capacity(16);
-    let param_map = &PARAM_MAP.read().expect("Param map lock was poisoned")[&device_id];
-    for name in &names {
-        let value = match &param_map[name].kind {
+    let mut entries = Vec::with_capacity(16);
+    for i in 0..16 {
+        if i >= params.len() {
+            break;
+        }
+        if bitmask & (1 << i) == 0 {
+            continue;
+        }
+        let param = match params[i].as_ref() {
+            Some(param) => param,
+            None => continue,
+        };
+        let value = match param.kind {
             ParamType::Uint8 => try_read(gil, cursor.read_u8())?,
             ParamType::Uint16 => try_read(gil, cursor.read_u16::<LittleEndian>())?,
             ParamType::Uint32 => try_read(gil, cursor.read_u32::<LittleEndian>())?,
@@ -182,10 +193,121 @@ pub fn parse_device_data(gil: Python, payload: PyBytes, device_id: u16) -> PyRes
                 objectify(gil, maybe_bool.unwrap())
             }
         };
-        values.push(value);
+        entries.push((param.name.as_str(), value));
+    }
+    Ok(PyList::new(gil, &entries.into_iter()
+                                .map(|tup| objectify(gil, tup))
+                                .collect::<Vec<_>>()))
+}
+
+/// Write `value` onto `out` according to `kind`, mirroring the `read_*` match arms in
+/// `parse_device_data`.
+fn write_param(gil: Python, out: &mut Vec<u8>, kind: ParamType, value: &PyObject) -> PyResult<()> {
+    match kind {
+        ParamType::Uint8 => out.write_u8(value.extract(gil)?),
+        ParamType::Uint16 => out.write_u16::<LittleEndian>(value.extract(gil)?),
+        ParamType::Uint32 => out.write_u32::<LittleEndian>(value.extract(gil)?),
+        ParamType::Uint64 => out.write_u64::<LittleEndian>(value.extract(gil)?),
+        ParamType::Int8 => out.write_i8(value.extract(gil)?),
+        ParamType::Int16 => out.write_i16::<LittleEndian>(value.extract(gil)?),
+        ParamType::Int32 => out.write_i32::<LittleEndian>(value.extract(gil)?),
+        ParamType::Int64 => out.write_i64::<LittleEndian>(value.extract(gil)?),
+        ParamType::Float => out.write_f32::<LittleEndian>(value.extract(gil)?),
+        ParamType::Double => out.write_f64::<LittleEndian>(value.extract(gil)?),
+        ParamType::Bool => out.write_u8(if value.extract::<bool>(gil)? { 1 } else { 0 }),
+    }.expect("writing to a Vec cannot fail");
+    Ok(())
+}
+
+/// Look up the `Parameter` named `name` in `param_map`, raising `ValueError` if it is
+/// unknown, or if its bit number doesn't fit the 16-bit bitmask.
+fn lookup_param<'a>(gil: Python, param_map: &'a ParamMap, name: &str) -> PyResult<&'a Parameter> {
+    let param = param_map.get(name).ok_or_else(|| value_error(gil, format!("unknown parameter: {}", name)))?;
+    if param.number >= 16 {
+        return Err(value_error(gil, format!("parameter {} has bit number {}, which doesn't fit a 16-bit bitmask", name, param.number)));
+    }
+    Ok(param)
+}
+
+/// Encode a `DeviceWrite` payload: a 16-bit bitmask of the supplied params followed by their
+/// values serialized in bit order.
+///
+/// Throws `ValueError` if `device_id` is invalid, a param name is unknown, or a param is not
+/// writable.
+pub fn encode_device_write(gil: Python, device_id: u16, params: PyDict) -> PyResult<PyBytes> {
+    let param_maps = PARAM_MAP.read().expect("Param map lock was poisoned");
+    let param_map = param_maps.get(&device_id)
+        .ok_or_else(|| value_error(gil, format!("invalid device_id: {}", device_id)))?;
+
+    let mut bitmask: u16 = 0;
+    let mut entries: Vec<(&Parameter, PyObject)> = Vec::with_capacity(params.len(gil));
+    for (key, value) in params.items(gil) {
+        let name: String = key.extract(gil)?;
+        let param = lookup_param(gil, param_map, &name)?;
+        if !param.write {
+            return Err(value_error(gil, format!("parameter {} is not writable", name)));
+        }
+        bitmask |= 1 << param.number;
+        entries.push((param, value));
+    }
+    entries.sort_by_key(|&(param, _)| param.number);
+
+    let mut payload = Vec::new();
+    payload.write_u16::<LittleEndian>(bitmask).expect("writing to a Vec cannot fail");
+    for (param, value) in entries {
+        write_param(gil, &mut payload, param.kind, &value)?;
+    }
+    Ok(PyBytes::new(gil, &payload))
+}
+
+/// Encode a `SubscriptionRequest` payload: a 16-bit bitmask of the requested params followed by
+/// the subscription delay in milliseconds.
+///
+/// Throws `ValueError` if `device_id` or a param name is invalid.
+pub fn encode_subscription_request(gil: Python, device_id: u16, params: PyList, delay: u16) -> PyResult<PyBytes> {
+    let param_maps = PARAM_MAP.read().expect("Param map lock was poisoned");
+    let param_map = param_maps.get(&device_id)
+        .ok_or_else(|| value_error(gil, format!("invalid device_id: {}", device_id)))?;
+
+    let mut bitmask: u16 = 0;
+    for name in params.iter(gil) {
+        let name: String = name.extract(gil)?;
+        let param = lookup_param(gil, param_map, &name)?;
+        bitmask |= 1 << param.number;
+    }
+
+    let mut payload = Vec::with_capacity(4);
+    payload.write_u16::<LittleEndian>(bitmask).expect("writing to a Vec cannot fail");
+    payload.write_u16::<LittleEndian>(delay).expect("writing to a Vec cannot fail");
+    Ok(PyBytes::new(gil, &payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode_device_write, initialize_parser_maps, parse_device_data};
+    use cpython::{Python, PyDict, PyBytes};
+
+    // Param `c` is numbered 3, leaving bit 2 unused, to guard against regressing the
+    // bit-index-by-array-position bug: the decode side must test bit 3 directly instead of
+    // stopping once it has walked past the third array slot.
+    const CONFIG: &str = r#"[{"id": 1, "name": "test_device", "params": [
+        {"name": "a", "number": 0, "type": "uint8_t", "read": true, "write": true},
+        {"name": "b", "number": 1, "type": "uint8_t", "read": true, "write": true},
+        {"name": "c", "number": 3, "type": "uint8_t", "read": true, "write": true}
+    ]}]"#;
+
+    #[test]
+    fn device_write_round_trips_through_parse_device_data_with_a_gapped_bit_number() {
+        let gil = Python::acquire_gil();
+        let py = gil.python();
+        initialize_parser_maps(py, CONFIG).unwrap();
+
+        let params = PyDict::new(py);
+        params.set_item(py, "c", 42u8).unwrap();
+        let payload: PyBytes = encode_device_write(py, 1, params).unwrap();
+
+        let decoded = parse_device_data(py, payload, 1).unwrap();
+        let decoded: Vec<(String, u8)> = decoded.iter(py).map(|item| item.extract(py).unwrap()).collect();
+        assert_eq!(decoded, vec![("c".to_string(), 42u8)]);
     }
-    Ok(PyList::new(gil, &names.into_iter()
-                              .zip(values)
-                              .map(|tup| objectify(gil, tup))
-                              .collect::<Vec<_>>()))
 }